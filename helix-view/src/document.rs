@@ -1,12 +1,18 @@
 use anyhow::Error;
+use encoding_rs::Encoding;
 use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::SystemTime;
 
 use helix_core::{
-    syntax::LOADER, ChangeSet, Diagnostic, History, Rope, Selection, State, Syntax, Transaction,
+    syntax::LOADER, ChangeSet, Diagnostic, Rope, Selection, State, Syntax, Transaction,
 };
 
+pub mod history;
+pub use history::History;
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Mode {
     Normal,
@@ -14,6 +20,167 @@ pub enum Mode {
     Goto,
 }
 
+/// The line ending a document was loaded with, so we can restore it on save
+/// instead of always writing Unix line endings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+impl Default for LineEnding {
+    fn default() -> Self {
+        LineEnding::Lf
+    }
+}
+
+/// Sniff a leading BOM to pick the encoding, falling back to a strict UTF-8
+/// decode and then the given default. Returns the encoding and the number of
+/// BOM bytes (0 if none were found) to skip before decoding.
+fn detect_encoding(bytes: &[u8], default: &'static Encoding) -> (&'static Encoding, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return (encoding, bom_len);
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        (encoding_rs::UTF_8, 0)
+    } else {
+        (default, 0)
+    }
+}
+
+/// Returns the BOM bytes for `encoding`, or an empty slice if the encoding
+/// doesn't carry one. Whether a BOM is written at all is decided by the
+/// caller from `Document::has_bom`.
+fn bom_bytes(encoding: &'static Encoding) -> &'static [u8] {
+    if encoding == encoding_rs::UTF_8 {
+        &[0xEF, 0xBB, 0xBF]
+    } else if encoding == encoding_rs::UTF_16LE {
+        &[0xFF, 0xFE]
+    } else if encoding == encoding_rs::UTF_16BE {
+        &[0xFE, 0xFF]
+    } else {
+        &[]
+    }
+}
+
+/// Encode `text` as `encoding` for writing to disk.
+///
+/// `encoding_rs` only ships UTF-16 *decoders*; `Encoding::encode()` for
+/// `UTF_16LE`/`UTF_16BE` silently encodes as the encoding's `output_encoding`
+/// (UTF-8) instead, which would pair a UTF-16 BOM with a UTF-8 body. Build
+/// the UTF-16 bytes by hand for those two encodings and defer to
+/// `encoding_rs` for everything else.
+fn encode_str(encoding: &'static Encoding, text: &str) -> Vec<u8> {
+    if encoding == encoding_rs::UTF_16LE {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    } else if encoding == encoding_rs::UTF_16BE {
+        text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    } else {
+        let (bytes, _, _) = encoding.encode(text);
+        bytes.into_owned()
+    }
+}
+
+/// Snapshot of the on-disk file used to detect external modifications
+/// between when we last read/wrote it and when we're about to overwrite it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileMetadata {
+    mtime: SystemTime,
+    len: u64,
+}
+
+impl FileMetadata {
+    fn from_path(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            mtime: metadata.modified()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Error returned by [`Document::save`].
+#[derive(Debug)]
+pub enum SaveError {
+    /// The file changed on disk (mtime/size) since this document last read
+    /// or wrote it; the caller should prompt the user before overwriting
+    /// rather than silently clobbering the external change.
+    ExternallyModified,
+    /// The document is marked read-only (unwritable file, or `--readonly`).
+    ReadOnly,
+    /// Document has no writable destination, or the write itself failed.
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::ExternallyModified => {
+                write!(f, "file has changed on disk since it was loaded")
+            }
+            SaveError::ReadOnly => write!(f, "buffer is read-only"),
+            SaveError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> Self {
+        SaveError::Other(err.into())
+    }
+}
+
+impl From<anyhow::Error> for SaveError {
+    fn from(err: anyhow::Error) -> Self {
+        SaveError::Other(err)
+    }
+}
+
+/// Path of the temporary sibling file we write to before renaming into
+/// place, so a crash mid-write never truncates the real file.
+fn tmp_file_path(path: &Path) -> PathBuf {
+    match path.file_name() {
+        Some(name) => {
+            let mut tmp_name = std::ffi::OsString::from(".");
+            tmp_name.push(name);
+            tmp_name.push(".tmp");
+            path.with_file_name(tmp_name)
+        }
+        None => path.with_extension("tmp"),
+    }
+}
+
+/// Whether we're actually able to write to the file at `path`. Catches
+/// permission bits as well as read-only filesystems, neither of which
+/// `fs::metadata` alone can tell us about.
+fn is_writable(path: &Path) -> bool {
+    std::fs::OpenOptions::new().write(true).open(path).is_ok()
+}
+
+/// Find the dominant line ending in `text` so we know what to restore on save.
+fn detect_line_ending(text: &str) -> LineEnding {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+
+    if crlf > lf {
+        LineEnding::Crlf
+    } else {
+        LineEnding::Lf
+    }
+}
+
 pub struct Document {
     pub state: State, // rope + selection
     /// File path on disk.
@@ -23,6 +190,33 @@ pub struct Document {
     pub mode: Mode,
     pub restore_cursor: bool,
 
+    /// The encoding the file was (or will be) read/written as. Defaults to
+    /// UTF-8 for new, in-memory-only documents.
+    pub encoding: &'static Encoding,
+    /// Whether the on-disk file started with a byte-order mark; if so we
+    /// write one back out on save.
+    pub has_bom: bool,
+    /// Line ending used by the file on disk; the rope itself always holds LF.
+    pub line_ending: LineEnding,
+    /// Set when the file isn't writable (permissions, read-only filesystem)
+    /// or the `--readonly` flag forces it; `save` refuses to write instead
+    /// of panicking or silently no-op'ing.
+    pub readonly: bool,
+
+    /// History node index that was on disk as of the last successful save,
+    /// updated once a `save()` future actually completes (shared so the
+    /// async save can update it). Compared against the current history
+    /// node to answer [`Document::is_modified`]; unlike a monotonic
+    /// counter, this correctly reads clean again after undoing/redoing
+    /// back to the saved node.
+    saved_history_node: Arc<AtomicUsize>,
+    /// Metadata of the file as last observed by us (at load, or after our
+    /// own save), used to detect external modifications before overwriting.
+    last_known_metadata: Arc<StdMutex<Option<FileMetadata>>>,
+    /// Serializes overlapping `save()` futures on this document so they
+    /// can't interleave their writes.
+    save_lock: Arc<smol::lock::Mutex<()>>,
+
     /// Tree-sitter AST tree
     pub syntax: Option<Syntax>,
     /// Corresponding language scope name. Usually `source.<lang>`.
@@ -61,34 +255,83 @@ impl Document {
     pub fn new(state: State) -> Self {
         let changes = ChangeSet::new(&state.doc);
         let old_state = None;
+        let history = History::new(&state.doc);
 
         Self {
             path: None,
             state,
             mode: Mode::Normal,
             restore_cursor: false,
+            encoding: encoding_rs::UTF_8,
+            has_bom: false,
+            line_ending: LineEnding::default(),
+            readonly: false,
+            saved_history_node: Arc::new(AtomicUsize::new(0)),
+            last_known_metadata: Arc::new(StdMutex::new(None)),
+            save_lock: Arc::new(smol::lock::Mutex::new(())),
             syntax: None,
             language: None,
             changes,
             old_state,
             diagnostics: Vec::new(),
             version: 0,
-            history: History::default(),
+            history,
             language_server: None,
         }
     }
 
     // TODO: passing scopes here is awkward
     // TODO: async fn?
-    pub fn load(path: PathBuf, scopes: &[String]) -> Result<Self, Error> {
-        use std::{env, fs::File, io::BufReader};
+    /// `default_encoding` is used to decode the file when it has no BOM and
+    /// isn't valid UTF-8 (e.g. a Latin-1/Windows-1252 file); with
+    /// `encoding_rs::UTF_8` such a file is decoded as UTF-8, with invalid
+    /// sequences replaced by U+FFFD.
+    ///
+    /// Signature note: `readonly` and `default_encoding` were added to this
+    /// function by earlier commits in this series. This tree is a partial
+    /// snapshot containing only `helix-view`'s `document.rs`/`history.rs` and
+    /// `helix-term`'s `main.rs` -- the editor/application layer that would
+    /// call `Document::load` isn't present here, so there are no in-tree
+    /// call sites to update for this signature change. Whoever merges this
+    /// against the full tree needs to update those call sites themselves.
+    pub fn load(
+        path: PathBuf,
+        scopes: &[String],
+        readonly: bool,
+        default_encoding: &'static Encoding,
+    ) -> Result<Self, Error> {
+        use std::{env, fs};
         let _current_dir = env::current_dir()?;
 
-        let doc = Rope::from_reader(BufReader::new(File::open(path.clone())?))?;
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                // create if not found: start with an empty, unsaved buffer
+                // at this path rather than failing outright
+                let mut doc = Self::new(State::new(Rope::new()));
+                doc.path = Some(path);
+                doc.readonly = readonly;
+                return Ok(doc);
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let (encoding, bom_len) = detect_encoding(&bytes, default_encoding);
+        let (decoded, _, _) = encoding.decode(&bytes[bom_len..]);
 
-        // TODO: create if not found
+        let line_ending = detect_line_ending(&decoded);
+        // strip \r from \r\n regardless of which ending is dominant, so a
+        // mostly-LF file with a handful of stray CRLF lines doesn't leak
+        // \r characters into the rope
+        let normalized = decoded.replace("\r\n", "\n");
+
+        let doc = Rope::from_str(&normalized);
 
         let mut doc = Self::new(State::new(doc));
+        doc.encoding = encoding;
+        doc.has_bom = bom_len > 0;
+        doc.line_ending = line_ending;
+        doc.readonly = readonly || !is_writable(&path);
 
         if let Some(language_config) = LOADER.language_config_for_file_name(path.as_path()) {
             let highlight_config = language_config.highlight_config(scopes).unwrap().unwrap();
@@ -111,35 +354,120 @@ impl Document {
         // canonicalize path to absolute value
         doc.path = Some(std::fs::canonicalize(path)?);
 
+        // remember what the file looked like so a later save() can tell if
+        // something else wrote to it in the meantime
+        let metadata = FileMetadata::from_path(doc.path.as_ref().unwrap()).ok();
+        *doc.last_known_metadata.lock().unwrap() = metadata;
+
+        // restore the undo tree from a previous session if we have one that
+        // still matches this file's contents
+        if let Ok(history) = History::load(doc.path.as_ref().unwrap(), &doc.state.doc) {
+            // the restored tree's current node is exactly what's on disk, so
+            // a freshly opened file reads as unmodified rather than as
+            // having diverged from history node 0
+            doc.saved_history_node
+                .store(history.current(), Ordering::SeqCst);
+            doc.history = history;
+        }
+
         Ok(doc)
     }
 
-    // TODO: do we need some way of ensuring two save operations on the same doc can't run at once?
-    // or is that handled by the OS/async layer
-    pub fn save(&self) -> impl Future<Output = Result<(), anyhow::Error>> {
+    /// Write the document to disk. Overlapping calls on the same `Document`
+    /// are serialized rather than interleaved; the write goes to a temporary
+    /// sibling file that's atomically renamed into place, and fails with
+    /// [`SaveError::ExternallyModified`] if the file changed underneath us
+    /// since it was loaded or last saved here.
+    pub fn save(&self) -> impl Future<Output = Result<(), SaveError>> {
         // we clone and move text + path into the future so that we asynchronously save the current
         // state without blocking any further edits.
 
         let text = self.text().clone();
         let path = self.path.clone().expect("Can't save with no path set!"); // TODO: handle no path
-
-        // TODO: mark changes up to now as saved
-        // TODO: mark dirty false
+        let encoding = self.encoding;
+        let has_bom = self.has_bom;
+        let line_ending = self.line_ending;
+        let history_node = self.history.current();
+        let readonly = self.readonly;
+        let save_lock = self.save_lock.clone();
+        let saved_history_node = self.saved_history_node.clone();
+        let last_known_metadata = self.last_known_metadata.clone();
 
         async move {
-            use smol::{fs::File, prelude::*};
-            let mut file = File::create(path).await?;
+            if readonly {
+                return Err(SaveError::ReadOnly);
+            }
 
-            // write all the rope chunks to file
-            for chunk in text.chunks() {
-                file.write_all(chunk.as_bytes()).await?;
+            // only one save at a time is ever writing to `path`
+            let _guard = save_lock.lock().await;
+
+            let previously_known = *last_known_metadata.lock().unwrap();
+            if let Some(previously_known) = previously_known {
+                if let Ok(current) = FileMetadata::from_path(&path) {
+                    if current != previously_known {
+                        return Err(SaveError::ExternallyModified);
+                    }
+                }
+            }
+
+            let tmp_path = tmp_file_path(&path);
+
+            let write_result: Result<(), SaveError> = async {
+                use smol::{fs::File, prelude::*};
+                let mut file = File::create(&tmp_path).await?;
+
+                if has_bom {
+                    file.write_all(bom_bytes(encoding)).await?;
+                }
+
+                // write all the rope chunks to file, restoring the original line
+                // ending and re-encoding into the document's original encoding
+                for chunk in text.chunks() {
+                    let chunk = if line_ending == LineEnding::Crlf {
+                        std::borrow::Cow::Owned(chunk.replace('\n', "\r\n"))
+                    } else {
+                        std::borrow::Cow::Borrowed(chunk)
+                    };
+
+                    let bytes = encode_str(encoding, &chunk);
+                    file.write_all(&bytes).await?;
+                }
+
+                file.flush().await?;
+                Ok(())
             }
-            // TODO: flush?
+            .await;
+
+            if let Err(err) = write_result {
+                // don't leave a half-written temp file lying around next to
+                // the real one
+                let _ = smol::fs::remove_file(&tmp_path).await;
+                return Err(err);
+            }
+
+            if let Err(err) = smol::fs::rename(&tmp_path, &path).await {
+                let _ = smol::fs::remove_file(&tmp_path).await;
+                return Err(err.into());
+            }
+
+            // mark changes up to this history node as saved / dirty false
+            saved_history_node.store(history_node, Ordering::SeqCst);
+            *last_known_metadata.lock().unwrap() = FileMetadata::from_path(&path).ok();
 
             Ok(())
         } // and_then notify save
     }
 
+    /// Whether the document has changes that haven't made it to disk yet:
+    /// either pending, uncommitted edits, or committed history that moved
+    /// away from the node that was last saved.
+    pub fn is_modified(&self) -> bool {
+        if !self.changes.is_empty() {
+            return true;
+        }
+        self.history.current() != self.saved_history_node.load(Ordering::SeqCst)
+    }
+
     pub fn set_language(&mut self, scope: &str, scopes: &[String]) {
         if let Some(language_config) = LOADER.language_config_for_scope(scope) {
             let highlight_config = language_config.highlight_config(scopes).unwrap().unwrap();
@@ -210,6 +538,20 @@ impl Document {
         success
     }
 
+    /// Commit the changes accumulated since the last checkpoint as a single
+    /// step in the undo tree. Call this at a logical edit boundary (e.g.
+    /// leaving insert mode) so the keystrokes since then undo together.
+    pub fn append_changes_to_history(&mut self) {
+        if self.changes.is_empty() {
+            return;
+        }
+
+        self.changes = ChangeSet::new(self.text());
+        self.old_state = None;
+
+        self.history.commit_revision(self.text());
+    }
+
     pub fn undo(&mut self) -> bool {
         if let Some(transaction) = self.history.undo() {
             self.version += 1;
@@ -237,6 +579,45 @@ impl Document {
         false
     }
 
+    /// Jump to the most recent edit older than where we are now, regardless
+    /// of undo branch.
+    pub fn earlier(&mut self) -> bool {
+        self.time_travel(History::earlier)
+    }
+
+    /// Jump to the oldest edit newer than where we are now, regardless of
+    /// undo branch.
+    pub fn later(&mut self) -> bool {
+        self.time_travel(History::later)
+    }
+
+    fn time_travel(&mut self, step: fn(&mut History) -> Vec<Transaction>) -> bool {
+        let transactions = step(&mut self.history);
+        if transactions.is_empty() {
+            return false;
+        }
+
+        self.version += 1;
+        let mut success = true;
+        for transaction in &transactions {
+            success &= self._apply(transaction);
+        }
+
+        // reset changeset to fix len
+        self.changes = ChangeSet::new(self.text());
+
+        success
+    }
+
+    /// Persist the undo tree so it can be restored the next time this file
+    /// is opened. Callers should invoke this when closing the document.
+    pub fn save_history(&self) -> anyhow::Result<()> {
+        match &self.path {
+            Some(path) => self.history.save(path),
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn mode(&self) -> Mode {
         self.mode
@@ -255,6 +636,15 @@ impl Document {
         &self.state.doc
     }
 
+    #[inline]
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.encoding = encoding;
+    }
+
     pub fn selection(&self) -> &Selection {
         &self.state.selection
     }
@@ -287,6 +677,47 @@ impl Document {
 mod test {
     use super::*;
 
+    #[test]
+    fn detect_line_ending_ignores_stray_crlf_in_lf_majority_file() {
+        assert_eq!(detect_line_ending("a\nb\r\nc\n"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn detect_line_ending_picks_crlf_when_dominant() {
+        assert_eq!(detect_line_ending("a\r\nb\r\nc\n"), LineEnding::Crlf);
+    }
+
+    #[test]
+    fn detect_encoding_prefers_bom_over_default() {
+        let bytes = [0xEF, 0xBB, 0xBF, b'h', b'i'];
+        let (encoding, bom_len) = detect_encoding(&bytes, encoding_rs::WINDOWS_1252);
+        assert_eq!(encoding, encoding_rs::UTF_8);
+        assert_eq!(bom_len, 3);
+    }
+
+    #[test]
+    fn detect_encoding_falls_back_to_configured_default_for_non_utf8() {
+        // 0xE9 on its own isn't valid UTF-8, but is a perfectly good
+        // Windows-1252 character (é); with no BOM this should defer to the
+        // caller's default rather than hardcoding UTF-8 and losing data.
+        let bytes = [0xE9, b' ', b'a'];
+        let (encoding, bom_len) = detect_encoding(&bytes, encoding_rs::WINDOWS_1252);
+        assert_eq!(encoding, encoding_rs::WINDOWS_1252);
+        assert_eq!(bom_len, 0);
+    }
+
+    #[test]
+    fn encode_str_writes_real_utf16_instead_of_falling_back_to_utf8() {
+        assert_eq!(
+            encode_str(encoding_rs::UTF_16LE, "hi"),
+            vec![b'h', 0x00, b'i', 0x00]
+        );
+        assert_eq!(
+            encode_str(encoding_rs::UTF_16BE, "hi"),
+            vec![0x00, b'h', 0x00, b'i']
+        );
+    }
+
     #[test]
     fn changeset_to_changes() {
         use helix_core::{Rope, State, Transaction};
@@ -0,0 +1,377 @@
+//! A branching undo tree.
+//!
+//! Each node stores a full text snapshot rather than a `helix_core::Transaction`:
+//! at this point `Transaction`/`ChangeSet` don't implement `Serialize`/
+//! `Deserialize` (that crate lives outside this tree), so a wire format built
+//! out of them isn't available to us. Snapshots are trivially (de)serializable
+//! and the `Transaction` needed to move between two nodes is cheap to
+//! reconstruct on demand from their snapshots (see [`transaction_between`]).
+//!
+//! Undoing never throws a branch away: editing after an undo just starts a
+//! new child of the current node, so old branches stay reachable through
+//! [`earlier`] and [`later`], which walk the whole tree by timestamp rather
+//! than by parent.
+//!
+//! Known tradeoff: storing a full snapshot per node means both the
+//! in-memory arena and the persisted `.history` file grow O(document size x
+//! revision count), not O(edit size x revision count) the way a
+//! transaction/inversion-based tree would. That's deliberate given the
+//! `Serialize`/`Deserialize` constraint above, but it means this isn't free
+//! for large files with long edit histories; revisit (e.g. periodic
+//! snapshot + inversion chains between them) if that turns out to matter in
+//! practice.
+//!
+//! [`earlier`]: History::earlier
+//! [`later`]: History::later
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use helix_core::{Rope, Transaction};
+use serde::{Deserialize, Serialize};
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Full document text at this point in history.
+    content: String,
+    timestamp: SystemTime,
+}
+
+/// An arena of undo nodes plus the index of the node we're currently at.
+/// Node 0 is the root, representing the document as it was first loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    nodes: Vec<Node>,
+    current: usize,
+}
+
+impl History {
+    /// Start a fresh undo tree rooted at `doc`'s current content.
+    pub fn new(doc: &Rope) -> Self {
+        Self {
+            nodes: vec![Node {
+                parent: None,
+                children: Vec::new(),
+                content: doc.to_string(),
+                timestamp: SystemTime::now(),
+            }],
+            current: 0,
+        }
+    }
+
+    /// Index of the node the document is currently at.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Record `doc`'s content as a new child of the current node and move
+    /// onto it. A no-op if `doc` matches the current node already.
+    pub fn commit_revision(&mut self, doc: &Rope) {
+        let content = doc.to_string();
+        if content == self.nodes[self.current].content {
+            return;
+        }
+
+        let new_index = self.nodes.len();
+        self.nodes.push(Node {
+            parent: Some(self.current),
+            children: Vec::new(),
+            content,
+            timestamp: SystemTime::now(),
+        });
+        self.nodes[self.current].children.push(new_index);
+        self.current = new_index;
+    }
+
+    /// Undo the current node, moving to its parent. Returns the transaction
+    /// to apply, or `None` if already at the root.
+    pub fn undo(&mut self) -> Option<Transaction> {
+        let parent = self.nodes[self.current].parent?;
+        let transaction =
+            transaction_between(&self.nodes[self.current].content, &self.nodes[parent].content);
+        self.current = parent;
+        Some(transaction)
+    }
+
+    /// Redo into the most recently created child of the current node.
+    /// Returns the transaction to apply, or `None` if there's no child to
+    /// redo into.
+    pub fn redo(&mut self) -> Option<Transaction> {
+        let target = self.nodes[self.current]
+            .children
+            .iter()
+            .copied()
+            .max_by_key(|&idx| self.nodes[idx].timestamp)?;
+
+        let transaction =
+            transaction_between(&self.nodes[self.current].content, &self.nodes[target].content);
+        self.current = target;
+        Some(transaction)
+    }
+
+    /// Jump to the most recent node whose timestamp is before the current
+    /// node's, regardless of which branch it's on. Returns the sequence of
+    /// transactions to apply in order to get there.
+    pub fn earlier(&mut self) -> Vec<Transaction> {
+        let now = self.nodes[self.current].timestamp;
+        let target = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|&(idx, node)| idx != self.current && node.timestamp < now)
+            .max_by_key(|&(_, node)| node.timestamp)
+            .map(|(idx, _)| idx);
+
+        target.map_or_else(Vec::new, |target| self.jump_to(target))
+    }
+
+    /// Jump to the oldest node whose timestamp is after the current node's.
+    /// Returns the sequence of transactions to apply in order to get there.
+    pub fn later(&mut self) -> Vec<Transaction> {
+        let now = self.nodes[self.current].timestamp;
+        let target = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|&(idx, node)| idx != self.current && node.timestamp > now)
+            .min_by_key(|&(_, node)| node.timestamp)
+            .map(|(idx, _)| idx);
+
+        target.map_or_else(Vec::new, |target| self.jump_to(target))
+    }
+
+    /// Walk from `idx` up to the root, collecting ancestor indices (`idx`
+    /// first).
+    fn ancestors(&self, mut idx: usize) -> Vec<usize> {
+        let mut path = vec![idx];
+        while let Some(parent) = self.nodes[idx].parent {
+            path.push(parent);
+            idx = parent;
+        }
+        path
+    }
+
+    /// Move from the current node to `target`, returning the transactions
+    /// (in application order) needed to get there.
+    fn jump_to(&mut self, target: usize) -> Vec<Transaction> {
+        let target_path = self.ancestors(target);
+        let target_set: HashSet<_> = target_path.iter().copied().collect();
+
+        // nodes visited walking from the current node up to the lowest
+        // common ancestor, inclusive on both ends
+        let mut up_path = vec![self.current];
+        let lca = loop {
+            let last = *up_path.last().unwrap();
+            if target_set.contains(&last) {
+                break last;
+            }
+            up_path.push(self.nodes[last].parent.expect("root is in every ancestor path"));
+        };
+
+        let lca_pos = target_path
+            .iter()
+            .position(|&idx| idx == lca)
+            .expect("lca is on target's ancestor path");
+
+        // full node chain from current down to target, via the lca
+        let mut chain = up_path;
+        chain.extend(target_path[..lca_pos].iter().rev());
+
+        let ops = chain
+            .windows(2)
+            .map(|pair| transaction_between(&self.nodes[pair[0]].content, &self.nodes[pair[1]].content))
+            .collect();
+
+        self.current = target;
+        ops
+    }
+}
+
+/// Build the `Transaction` that turns `old` into `new`, by replacing the
+/// span between their common prefix and common suffix. Not a minimal diff,
+/// but always correct, and all we can do without the original keystroke
+/// transactions (see the module docs).
+fn transaction_between(old: &str, new: &str) -> Transaction {
+    let old_chars: Vec<char> = old.chars().collect();
+    let new_chars: Vec<char> = new.chars().collect();
+
+    let prefix_len = old_chars
+        .iter()
+        .zip(new_chars.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let max_suffix = (old_chars.len() - prefix_len).min(new_chars.len() - prefix_len);
+    let suffix_len = old_chars[prefix_len..]
+        .iter()
+        .rev()
+        .zip(new_chars[prefix_len..].iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let from = prefix_len;
+    let to = old_chars.len() - suffix_len;
+    let insert: String = new_chars[prefix_len..new_chars.len() - suffix_len]
+        .iter()
+        .collect();
+
+    Transaction::change(
+        &Rope::from(old),
+        std::iter::once((from, to, if insert.is_empty() { None } else { Some(insert.into()) })),
+    )
+}
+
+/// Path of the history file for a document at `path`, a dot-file living
+/// next to it (mirrors the `.foo.tmp` convention used for atomic saves).
+pub fn file_path(path: &Path) -> PathBuf {
+    match path.file_name() {
+        Some(name) => {
+            let mut history_name = std::ffi::OsString::from(".");
+            history_name.push(name);
+            history_name.push(".history");
+            path.with_file_name(history_name)
+        }
+        None => path.with_extension("history"),
+    }
+}
+
+/// On-disk representation of a persisted [`History`], guarded by a format
+/// version.
+#[derive(Serialize, Deserialize)]
+struct HistoryFile {
+    format_version: u32,
+    history: History,
+}
+
+impl History {
+    /// Load a persisted undo tree for `path`, refusing it if its format
+    /// version isn't `FORMAT_VERSION` or `doc` doesn't match the content the tree's
+    /// current node represents (that's the buffer state the file on disk
+    /// actually holds; validating against the root would reject the tree on
+    /// every save-then-reopen, since the root is the document as first
+    /// loaded, not as last saved).
+    pub fn load(path: &Path, doc: &Rope) -> anyhow::Result<History> {
+        let bytes = std::fs::read(file_path(path))?;
+        let on_disk: HistoryFile = serde_json::from_slice(&bytes)?;
+
+        anyhow::ensure!(
+            on_disk.format_version == FORMAT_VERSION,
+            "unsupported history file format version {}",
+            on_disk.format_version,
+        );
+
+        let current = &on_disk.history.nodes[on_disk.history.current];
+        anyhow::ensure!(
+            current.content == doc.to_string(),
+            "history file doesn't match the current document contents",
+        );
+
+        Ok(on_disk.history)
+    }
+
+    /// Persist this undo tree for `path`.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let on_disk = HistoryFile {
+            format_version: FORMAT_VERSION,
+            history: self.clone(),
+        };
+
+        let bytes = serde_json::to_vec(&on_disk)?;
+        std::fs::write(file_path(path), bytes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn undo_then_edit_starts_a_new_branch() {
+        let mut history = History::new(&Rope::from("a"));
+        history.commit_revision(&Rope::from("ab"));
+        assert_eq!(history.current(), 1);
+
+        history.undo();
+        assert_eq!(history.current(), 0);
+
+        // editing after an undo must not overwrite the branch we left;
+        // it should start a sibling of it instead
+        history.commit_revision(&Rope::from("ac"));
+        assert_eq!(history.current(), 2);
+        assert_eq!(history.nodes[0].children, vec![1, 2]);
+
+        history.undo();
+        assert_eq!(history.current(), 0);
+
+        // redo follows the most recently created child: the "ac" branch
+        history.redo();
+        assert_eq!(history.current(), 2);
+        assert_eq!(history.nodes[2].content, "ac");
+    }
+
+    #[test]
+    fn earlier_and_later_cross_branches() {
+        let mut history = History::new(&Rope::from("a"));
+        history.commit_revision(&Rope::from("ab")); // node 1
+        history.undo();
+        history.commit_revision(&Rope::from("ac")); // node 2, sibling of node 1
+
+        // sitting on node 2 (the latest in time); earlier() should walk back
+        // across branches to node 1 rather than stopping at node 2's parent
+        history.earlier();
+        assert_eq!(history.current(), 1);
+
+        history.later();
+        assert_eq!(history.current(), 2);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut history = History::new(&Rope::from("hello"));
+        history.commit_revision(&Rope::from("hello world"));
+
+        let path = std::env::temp_dir().join(format!(
+            "helix-history-test-{}-{}.txt",
+            std::process::id(),
+            history.nodes.len()
+        ));
+        // `file_path` derives the history file's name from this sibling, it
+        // doesn't need to actually hold the same content for this test
+        std::fs::write(&path, "hello world").unwrap();
+
+        history.save(&path).unwrap();
+        let loaded = History::load(&path, &Rope::from("hello world")).unwrap();
+
+        assert_eq!(loaded.current(), history.current());
+        assert_eq!(loaded.nodes.len(), history.nodes.len());
+        assert_eq!(loaded.nodes[1].content, "hello world");
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(file_path(&path)).ok();
+    }
+
+    #[test]
+    fn load_rejects_tree_that_doesnt_match_current_document_contents() {
+        let history = History::new(&Rope::from("hello"));
+
+        let path = std::env::temp_dir().join(format!(
+            "helix-history-test-mismatch-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&path, "hello").unwrap();
+        history.save(&path).unwrap();
+
+        let result = History::load(&path, &Rope::from("goodbye"));
+        assert!(result.is_err());
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(file_path(&path)).ok();
+    }
+}